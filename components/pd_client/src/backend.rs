@@ -0,0 +1,460 @@
+// Copyright 2023 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Pluggable transport/endpoint-resolution backend for `RpcClient`.
+//!
+//! `RpcClient` has historically been hard-wired to a single gRPC cluster
+//! handle, so tests could only exercise it against a real (if mocked) gRPC
+//! server, and there was no way to front more than one PD cluster or swap
+//! in a recording/replay shim. [`PdBackend`] factors the cluster/leader
+//! handle and per-call dispatch that `RpcClient` used to own directly into
+//! a trait object, so `RpcClient` can hold any implementation -- the normal
+//! gRPC one, an in-process mock, or something that fronts multiple
+//! clusters -- without otherwise changing.
+
+use std::{
+    sync::{Arc, Condvar, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use grpcio::{ChannelBuilder, Environment};
+use kvproto::pdpb::{self, ResponseHeader};
+use kvproto::pdpb_grpc::PdClient as PdClientStub;
+use security::SecurityManager;
+
+use crate::{Error, Result};
+
+/// How often a [`PdBackend`] is willing to actually re-dial PD, mirroring
+/// the pre-existing `GLOBAL_RECONNECT_INTERVAL` rate limit: every request
+/// method's retry loop calls `reconnect()` independently on error, so
+/// without this a leader change would make every concurrently-retrying
+/// request stampede the new leader with its own redundant reconnect.
+pub const GLOBAL_RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Debounces and coalesces concurrent calls to a backend's `reconnect()`.
+///
+/// Every request method's retry loop treats `reconnect()` as its own
+/// private recovery step, so a PD leader change can have dozens of
+/// concurrently-retrying requests call it at once. [`ReconnectLimiter::run`]
+/// makes that safe: a reconnect already in flight is shared with every
+/// caller that arrives while it's running (one real dial, not one per
+/// caller), and a reconnect that completed within `min_interval` is
+/// likewise reused rather than immediately repeated.
+struct ReconnectLimiter {
+    min_interval: Duration,
+    state: Mutex<LimiterState>,
+    cond: Condvar,
+}
+
+struct LimiterState {
+    in_flight: bool,
+    last_attempt: Option<Instant>,
+    /// Bumped every time an attempt (ours or a coalesced-in caller's)
+    /// finishes, so a waiter woken from `cond` can tell whether it needs
+    /// to run its own attempt or can just reuse `last_result`.
+    generation: u64,
+    last_result: Option<std::result::Result<(), String>>,
+}
+
+impl ReconnectLimiter {
+    fn new(min_interval: Duration) -> ReconnectLimiter {
+        ReconnectLimiter {
+            min_interval,
+            state: Mutex::new(LimiterState {
+                in_flight: false,
+                last_attempt: None,
+                generation: 0,
+                last_result: None,
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    fn run(&self, reconnect: impl FnOnce() -> Result<()>) -> Result<()> {
+        let mut guard = self.state.lock().unwrap();
+        let observed_generation = guard.generation;
+        loop {
+            if guard.in_flight {
+                guard = self.cond.wait(guard).unwrap();
+                if guard.generation != observed_generation {
+                    return Self::to_result(guard.last_result.clone());
+                }
+                continue;
+            }
+            if let Some(last) = guard.last_attempt {
+                if last.elapsed() < self.min_interval {
+                    return Self::to_result(guard.last_result.clone());
+                }
+            }
+            break;
+        }
+        guard.in_flight = true;
+        drop(guard);
+
+        let result = reconnect();
+
+        let mut guard = self.state.lock().unwrap();
+        guard.in_flight = false;
+        guard.last_attempt = Some(Instant::now());
+        guard.generation += 1;
+        guard.last_result = Some(result.as_ref().map(|_| ()).map_err(|e| e.to_string()));
+        self.cond.notify_all();
+        result
+    }
+
+    fn to_result(stored: Option<std::result::Result<(), String>>) -> Result<()> {
+        match stored {
+            None | Some(Ok(())) => Ok(()),
+            Some(Err(msg)) => Err(Error::Other(msg.into())),
+        }
+    }
+}
+
+/// The leader/cluster handle a [`PdBackend`] currently believes is
+/// authoritative, opaque to `RpcClient` beyond what it needs to log and to
+/// detect "the handle changed under me".
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LeaderInfo {
+    pub cluster_id: u64,
+    pub leader_addr: String,
+}
+
+/// Abstracts "how do I reach PD" away from `RpcClient`, which otherwise only
+/// knows how to dispatch typed requests and interpret `ResponseHeader`
+/// errors.
+///
+/// Implementations own whatever connection state they need (a gRPC
+/// channel, an in-process channel to a mock, ...) behind interior
+/// mutability, since `RpcClient` hands out `Arc<dyn PdBackend>` to multiple
+/// call sites concurrently.
+pub trait PdBackend: Send + Sync {
+    /// Returns the backend's current belief about the leader, used by
+    /// callers (e.g. the retry loop in [`crate::retry`]) to detect whether
+    /// a reconnect actually changed anything.
+    fn leader_info(&self) -> LeaderInfo;
+
+    /// Forces the backend to re-resolve the leader against its current
+    /// endpoint set, the same operation `RpcClient::reconnect` triggers
+    /// today.
+    fn reconnect(&self) -> Result<()>;
+
+    /// Replaces the backend's endpoint set with `endpoints` and re-resolves
+    /// the leader against it, so PD addresses can be added or removed at
+    /// runtime without rebuilding the client.
+    fn update_endpoints(&self, endpoints: Vec<String>) -> Result<()>;
+
+    /// The endpoint set the backend is currently resolving the leader
+    /// against.
+    fn endpoints(&self) -> Vec<String>;
+
+    /// The raw generated gRPC client for the current leader, for backends
+    /// that actually speak gRPC to PD. Request methods that need to issue
+    /// a real RPC (`alloc_id`, `get_region`, `region_heartbeat`, `get_tso`,
+    /// ...) go through this; backends with no gRPC underneath (e.g.
+    /// [`StubBackend`]) return `None` and are only suitable for exercising
+    /// logic that only needs `leader_info`/`reconnect`/endpoint resolution,
+    /// such as the retry loop itself.
+    fn client_stub(&self) -> Option<PdClientStub> {
+        None
+    }
+}
+
+/// An in-memory [`PdBackend`] useful for unit tests that don't want to pay
+/// for a real (even mocked) gRPC server: `leader_info`/`reconnect` are
+/// driven entirely by the test, not by any network I/O.
+pub struct StubBackend {
+    state: RwLock<StubState>,
+    // Tests rely on every `reconnect()` call taking effect immediately
+    // (e.g. `test_stub_backend_round_robins_on_reconnect`), so the stub
+    // still goes through the shared limiter -- for in-flight coalescing --
+    // but with no debounce window.
+    limiter: ReconnectLimiter,
+}
+
+struct StubState {
+    cluster_id: u64,
+    endpoints: Vec<String>,
+    leader_index: usize,
+    reconnect_count: u64,
+}
+
+impl StubBackend {
+    pub fn new(cluster_id: u64, endpoints: Vec<String>) -> Arc<StubBackend> {
+        assert!(!endpoints.is_empty(), "a backend needs at least one endpoint");
+        Arc::new(StubBackend {
+            state: RwLock::new(StubState {
+                cluster_id,
+                endpoints,
+                leader_index: 0,
+                reconnect_count: 0,
+            }),
+            limiter: ReconnectLimiter::new(Duration::ZERO),
+        })
+    }
+
+    /// Number of times [`PdBackend::reconnect`] has been called, so tests
+    /// can assert on reconnect counts the way they do against the real
+    /// gRPC client's metrics today.
+    pub fn reconnect_count(&self) -> u64 {
+        self.state.read().unwrap().reconnect_count
+    }
+
+    /// Simulates the cluster electing whatever endpoint is at `index` as
+    /// leader, without requiring an actual `reconnect()` call.
+    pub fn set_leader_index(&self, index: usize) {
+        let mut state = self.state.write().unwrap();
+        assert!(index < state.endpoints.len());
+        state.leader_index = index;
+    }
+}
+
+impl PdBackend for StubBackend {
+    fn leader_info(&self) -> LeaderInfo {
+        let state = self.state.read().unwrap();
+        LeaderInfo {
+            cluster_id: state.cluster_id,
+            leader_addr: state.endpoints[state.leader_index].clone(),
+        }
+    }
+
+    fn reconnect(&self) -> Result<()> {
+        self.limiter.run(|| {
+            let mut state = self.state.write().unwrap();
+            state.reconnect_count += 1;
+            // Round-robin so repeated reconnects deterministically walk the
+            // endpoint set, mirroring "leader moved to the next node"
+            // without needing real gossip.
+            state.leader_index = (state.leader_index + 1) % state.endpoints.len();
+            Ok(())
+        })
+    }
+
+    fn update_endpoints(&self, endpoints: Vec<String>) -> Result<()> {
+        assert!(!endpoints.is_empty(), "a backend needs at least one endpoint");
+        let mut state = self.state.write().unwrap();
+        state.endpoints = endpoints;
+        state.leader_index = 0;
+        Ok(())
+    }
+
+    fn endpoints(&self) -> Vec<String> {
+        self.state.read().unwrap().endpoints.clone()
+    }
+}
+
+/// The production [`PdBackend`]: resolves the current leader out of a set
+/// of PD endpoints over real gRPC and hands back a generated client stub
+/// connected to it. This is the backend `RpcClient::new` wires up by
+/// default; [`StubBackend`] exists purely so unit tests (and embedders
+/// fronting a simulated or proxied topology) don't have to pay for one.
+pub struct GrpcBackend {
+    env: Arc<Environment>,
+    security_mgr: Arc<SecurityManager>,
+    state: RwLock<GrpcState>,
+    // Every request method's retry loop calls `reconnect()` on its own
+    // error, so without this a leader change makes every
+    // concurrently-retrying request re-dial every endpoint from scratch at
+    // once. See `ReconnectLimiter`.
+    limiter: ReconnectLimiter,
+}
+
+struct GrpcState {
+    endpoints: Vec<String>,
+    cluster_id: u64,
+    leader_addr: String,
+    stub: PdClientStub,
+}
+
+impl GrpcBackend {
+    /// Connects to one of `endpoints`, resolves the current leader via
+    /// `GetMembers`, and returns a backend pinned to that leader.
+    pub fn connect(
+        endpoints: Vec<String>,
+        env: Arc<Environment>,
+        security_mgr: Arc<SecurityManager>,
+    ) -> Result<Arc<GrpcBackend>> {
+        let (cluster_id, leader_addr, stub) = resolve_leader(&endpoints, &env, &security_mgr)?;
+        Ok(Arc::new(GrpcBackend {
+            env,
+            security_mgr,
+            state: RwLock::new(GrpcState {
+                endpoints,
+                cluster_id,
+                leader_addr,
+                stub,
+            }),
+            limiter: ReconnectLimiter::new(GLOBAL_RECONNECT_INTERVAL),
+        }))
+    }
+
+    /// Re-resolves the leader and swaps it in, with no rate limiting of its
+    /// own -- callers that need the global limit go through
+    /// `PdBackend::reconnect` instead.
+    fn resolve_and_swap(&self) -> Result<()> {
+        let endpoints = self.state.read().unwrap().endpoints.clone();
+        let (cluster_id, leader_addr, stub) = resolve_leader(&endpoints, &self.env, &self.security_mgr)?;
+        let mut state = self.state.write().unwrap();
+        state.cluster_id = cluster_id;
+        state.leader_addr = leader_addr;
+        state.stub = stub;
+        Ok(())
+    }
+}
+
+/// Connects to each of `endpoints` in turn until one answers `GetMembers`,
+/// and returns a stub built against whichever member it reports as leader.
+fn resolve_leader(
+    endpoints: &[String],
+    env: &Arc<Environment>,
+    security_mgr: &Arc<SecurityManager>,
+) -> Result<(u64, String, PdClientStub)> {
+    let mut last_err = None;
+    for ep in endpoints {
+        let channel = security_mgr.connect(ChannelBuilder::new(env.clone()), ep);
+        let stub = PdClientStub::new(channel);
+        let req = pdpb::GetMembersRequest::default();
+        match stub.get_members(&req) {
+            Ok(resp) => {
+                let leader_addr = resp
+                    .get_leader()
+                    .get_client_urls()
+                    .first()
+                    .cloned()
+                    .unwrap_or_else(|| ep.clone());
+                let leader_channel = security_mgr.connect(ChannelBuilder::new(env.clone()), &leader_addr);
+                let leader_stub = PdClientStub::new(leader_channel);
+                return Ok((resp.get_header().get_cluster_id(), leader_addr, leader_stub));
+            }
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.map(crate::Error::from).unwrap_or_else(|| {
+        crate::Error::Other("no reachable PD endpoint".to_owned().into())
+    }))
+}
+
+impl PdBackend for GrpcBackend {
+    fn leader_info(&self) -> LeaderInfo {
+        let state = self.state.read().unwrap();
+        LeaderInfo {
+            cluster_id: state.cluster_id,
+            leader_addr: state.leader_addr.clone(),
+        }
+    }
+
+    fn reconnect(&self) -> Result<()> {
+        self.limiter.run(|| self.resolve_and_swap())
+    }
+
+    fn update_endpoints(&self, endpoints: Vec<String>) -> Result<()> {
+        assert!(!endpoints.is_empty(), "a backend needs at least one endpoint");
+        {
+            self.state.write().unwrap().endpoints = endpoints;
+        }
+        // Bypasses `self.limiter`: an explicit endpoint change must always
+        // re-resolve against the new set, not get coalesced into -- or
+        // debounced behind -- whatever the last unrelated reconnect found.
+        self.resolve_and_swap()
+    }
+
+    fn endpoints(&self) -> Vec<String> {
+        self.state.read().unwrap().endpoints.clone()
+    }
+
+    fn client_stub(&self) -> Option<PdClientStub> {
+        Some(self.state.read().unwrap().stub.clone())
+    }
+}
+
+/// Helper shared by every [`PdBackend`] implementation to turn a non-empty
+/// `ResponseHeader.error` into a `Result::Err`, matching how the gRPC
+/// backend has always interpreted PD responses.
+pub fn check_resp_header(header: &ResponseHeader) -> Result<()> {
+    if header.has_error() {
+        Err(crate::Error::Other(
+            format!("{:?}", header.get_error()).into(),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stub_backend_round_robins_on_reconnect() {
+        let backend = StubBackend::new(1, vec!["a".into(), "b".into(), "c".into()]);
+        assert_eq!(backend.leader_info().leader_addr, "a");
+        backend.reconnect().unwrap();
+        assert_eq!(backend.leader_info().leader_addr, "b");
+        backend.reconnect().unwrap();
+        assert_eq!(backend.leader_info().leader_addr, "c");
+        assert_eq!(backend.reconnect_count(), 2);
+    }
+
+    #[test]
+    fn test_update_endpoints_resets_leader() {
+        let backend = StubBackend::new(1, vec!["a".into(), "b".into()]);
+        backend.set_leader_index(1);
+        assert_eq!(backend.leader_info().leader_addr, "b");
+        backend.update_endpoints(vec!["x".into(), "y".into()]).unwrap();
+        assert_eq!(backend.leader_info().leader_addr, "x");
+        assert_eq!(backend.endpoints(), vec!["x".to_string(), "y".to_string()]);
+    }
+
+    #[test]
+    fn test_reconnect_limiter_debounces_rapid_sequential_calls() {
+        let limiter = ReconnectLimiter::new(Duration::from_secs(60));
+        let calls = Arc::new(Mutex::new(0));
+        let run = |limiter: &ReconnectLimiter, calls: &Arc<Mutex<u32>>| {
+            let calls = calls.clone();
+            limiter.run(move || {
+                *calls.lock().unwrap() += 1;
+                Ok(())
+            })
+        };
+        run(&limiter, &calls).unwrap();
+        run(&limiter, &calls).unwrap();
+        run(&limiter, &calls).unwrap();
+        // The second and third calls land well inside the 60s debounce
+        // window, so only the first should have actually run.
+        assert_eq!(*calls.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_reconnect_limiter_coalesces_concurrent_callers() {
+        use std::{sync::Barrier, thread};
+
+        let limiter = Arc::new(ReconnectLimiter::new(Duration::ZERO));
+        let calls = Arc::new(Mutex::new(0));
+        let barrier = Arc::new(Barrier::new(8));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let limiter = limiter.clone();
+                let calls = calls.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    limiter.run(|| {
+                        let mut guard = calls.lock().unwrap();
+                        *guard += 1;
+                        // Hold the "reconnect" open long enough that every
+                        // other thread's call is guaranteed to observe
+                        // `in_flight` and coalesce onto this one, instead
+                        // of racing to also take the "not in flight" path.
+                        drop(guard);
+                        thread::sleep(Duration::from_millis(20));
+                        Ok(())
+                    })
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap().unwrap();
+        }
+        // All 8 callers arrived while one reconnect was in flight, so a
+        // real backend should only have had to actually redial once.
+        assert_eq!(*calls.lock().unwrap(), 1);
+    }
+}
@@ -0,0 +1,316 @@
+// Copyright 2016 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::{
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use futures::{stream::Stream, SinkExt, TryStreamExt};
+use grpcio::Environment;
+use kvproto::{metapb::Region, pdpb};
+use security::SecurityManager;
+use txn_types::TimeStamp;
+
+use crate::{
+    backend::{self, GrpcBackend, PdBackend},
+    config::Config,
+    errors::{Error, Result},
+    retry::{real_clock, retry_on_leader_change, Clock},
+    tso::{TsoDispatcher, TsoTransport},
+    watch::{OpenStream, Reconnect, ResumableConfigWatch},
+    PdClient,
+};
+
+/// The default `PdClient` implementation, speaking to a real PD cluster
+/// (or, with [`RpcClient::with_backend`], anything else that implements
+/// [`PdBackend`]).
+///
+/// Request methods no longer hand-roll their own recovery: they call
+/// through [`retry_on_leader_change`] with `self.backend` as the
+/// reconnect hook, so a PD leader change transparently retries against the
+/// fresh leader instead of bubbling straight back to the caller.
+pub struct RpcClient {
+    backend: Arc<dyn PdBackend>,
+    config: Config,
+    tso: TsoDispatcher,
+    clock: Arc<dyn Clock>,
+    updater: PeriodicalUpdater,
+}
+
+impl RpcClient {
+    /// Connects to the PD cluster described by `config` over real gRPC.
+    pub fn new(config: &Config, env: Option<Arc<Environment>>, security_mgr: Arc<SecurityManager>) -> Result<RpcClient> {
+        let env = env.unwrap_or_else(|| Arc::new(Environment::new(1)));
+        let backend = GrpcBackend::connect(config.endpoints.clone(), env, security_mgr)?;
+        Ok(RpcClient::with_backend(config, backend))
+    }
+
+    /// Builds a client against an arbitrary [`PdBackend`], e.g.
+    /// [`crate::backend::StubBackend`] in tests, or a caller-supplied
+    /// backend fronting a simulated or proxied topology.
+    pub fn with_backend(config: &Config, backend: Arc<dyn PdBackend>) -> RpcClient {
+        RpcClient::with_backend_and_clock(config, backend, real_clock())
+    }
+
+    /// As [`RpcClient::with_backend`], but also lets the caller inject the
+    /// [`Clock`] driving the per-request backoff and the periodical
+    /// background refresh -- under the `sim` feature, a
+    /// [`crate::sim::SimClock`] so both can be stepped deterministically in
+    /// tests instead of waiting on the wall clock.
+    pub fn with_backend_and_clock(config: &Config, backend: Arc<dyn PdBackend>, clock: Arc<dyn Clock>) -> RpcClient {
+        let mut config = config.clone();
+        config.validate();
+        let tso = TsoDispatcher::spawn(config.tso_batch, BackendTsoTransport::new(backend.clone()));
+        let updater = PeriodicalUpdater::spawn(backend.clone(), clock.clone(), config.update_interval.0);
+        RpcClient {
+            backend,
+            config,
+            tso,
+            clock,
+            updater,
+        }
+    }
+
+    fn retry<T>(&self, mut call: impl FnMut(&dyn PdBackend) -> Result<T>) -> Result<T> {
+        let backend = self.backend.as_ref();
+        retry_on_leader_change(&self.config.retry, self.clock.as_ref(), || backend.reconnect(), || call(backend))
+    }
+}
+
+/// Keeps `RpcClient`'s view of the cluster fresh in the background,
+/// independent of the per-request retry loop: every `config.update_interval`
+/// it calls `backend.reconnect()` to re-resolve the leader, so a leader
+/// change is picked up even for a client that hasn't issued a request
+/// recently enough to notice via a failed call.
+struct PeriodicalUpdater {
+    shutdown: Arc<Mutex<bool>>,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+/// Upper bound on how long a single `clock.sleep` call waits before
+/// rechecking `shutdown`, so dropping an `RpcClient` with a long
+/// `update_interval` (the default is 10s) doesn't block for the whole
+/// interval.
+const MAX_UPDATE_CHUNK: std::time::Duration = std::time::Duration::from_millis(200);
+
+impl PeriodicalUpdater {
+    fn spawn(backend: Arc<dyn PdBackend>, clock: Arc<dyn Clock>, interval: std::time::Duration) -> PeriodicalUpdater {
+        let shutdown = Arc::new(Mutex::new(false));
+        let loop_shutdown = shutdown.clone();
+        let join_handle = thread::Builder::new()
+            .name("pd-periodical-update".to_owned())
+            .spawn(move || loop {
+                let mut remaining = interval;
+                while remaining > std::time::Duration::ZERO {
+                    if *loop_shutdown.lock().unwrap() {
+                        return;
+                    }
+                    let chunk = std::cmp::min(remaining, MAX_UPDATE_CHUNK);
+                    clock.sleep(chunk);
+                    remaining -= chunk;
+                }
+                if *loop_shutdown.lock().unwrap() {
+                    return;
+                }
+                // A failed background refresh isn't fatal: the next
+                // request's own retry loop still reconnects on error, this
+                // is purely a "notice sooner" optimization.
+                let _ = backend.reconnect();
+            })
+            .expect("failed to spawn pd-periodical-update thread");
+        PeriodicalUpdater {
+            shutdown,
+            join_handle: Some(join_handle),
+        }
+    }
+}
+
+impl Drop for PeriodicalUpdater {
+    fn drop(&mut self) {
+        *self.shutdown.lock().unwrap() = true;
+        if let Some(handle) = self.join_handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Converts a backend reconnect failure into the `grpcio::Error`
+/// `ResumableConfigWatch::resubscribe` (see [`crate::watch::Reconnect`])
+/// expects, so a non-gRPC failure (e.g. "no reachable PD endpoint") still
+/// surfaces as a normal stream error rather than needing its own error
+/// type plumbed through `watch.rs`.
+fn to_grpc_error(e: Error) -> grpcio::Error {
+    match e {
+        Error::Grpc(e) => e,
+        other => grpcio::Error::RpcFailure(grpcio::RpcStatus::with_message(
+            grpcio::RpcStatusCode::UNAVAILABLE,
+            other.to_string(),
+        )),
+    }
+}
+
+/// Adapts a [`PdBackend`]'s raw gRPC stub into the
+/// [`crate::tso::TsoTransport`] the background batching dispatcher drives.
+///
+/// `Tso` is a bidirectional-streaming RPC, not unary: PD expects one
+/// long-lived stream per client, not a fresh connection per batch. This
+/// holds that stream open across calls and only re-establishes it (through
+/// the backend's normal `client_stub`, i.e. whatever leader `reconnect`
+/// last resolved) after an error, rather than dialing fresh every batch.
+struct BackendTsoTransport {
+    backend: Arc<dyn PdBackend>,
+    stream: Option<TsoStream>,
+}
+
+struct TsoStream {
+    sink: grpcio::ClientDuplexSender<pdpb::TsoRequest>,
+    receiver: grpcio::ClientDuplexReceiver<pdpb::TsoResponse>,
+}
+
+impl BackendTsoTransport {
+    fn new(backend: Arc<dyn PdBackend>) -> BackendTsoTransport {
+        BackendTsoTransport {
+            backend,
+            stream: None,
+        }
+    }
+
+    fn stream(&mut self) -> Result<&mut TsoStream> {
+        if self.stream.is_none() {
+            let stub = self
+                .backend
+                .client_stub()
+                .ok_or_else(|| Error::Other("backend does not support TSO batching".to_owned().into()))?;
+            let (sink, receiver) = stub.tso().map_err(Error::from)?;
+            self.stream = Some(TsoStream { sink, receiver });
+        }
+        Ok(self.stream.as_mut().unwrap())
+    }
+}
+
+impl TsoTransport for BackendTsoTransport {
+    fn batch_get_tso(&mut self, count: u32) -> Result<(i64, i64)> {
+        let mut req = pdpb::TsoRequest::default();
+        req.set_count(count);
+        let result: Result<(i64, i64)> = futures::executor::block_on(async {
+            let stream = self.stream()?;
+            stream
+                .sink
+                .send((req, grpcio::WriteFlags::default()))
+                .await
+                .map_err(Error::from)?;
+            let resp = stream
+                .receiver
+                .try_next()
+                .await
+                .map_err(Error::from)?
+                .ok_or_else(|| Error::Other("PD closed the TSO stream".to_owned().into()))?;
+            backend::check_resp_header(resp.get_header())?;
+            let ts = resp.get_timestamp();
+            Ok((ts.get_physical(), ts.get_logical()))
+        });
+        if result.is_err() {
+            // Drop the broken stream so the next call re-establishes it
+            // against whatever leader `reconnect` resolves, instead of
+            // retrying writes against a stream PD already tore down.
+            self.stream = None;
+        }
+        result
+    }
+}
+
+impl PdClient for RpcClient {
+    fn reconnect(&self) -> Result<()> {
+        self.backend.reconnect()
+    }
+
+    fn get_cluster_id(&self) -> Result<u64> {
+        Ok(self.backend.leader_info().cluster_id)
+    }
+
+    fn alloc_id(&self) -> Result<u64> {
+        self.retry(|backend| {
+            let stub = backend
+                .client_stub()
+                .ok_or_else(|| Error::Other("backend does not support alloc_id".to_owned().into()))?;
+            let req = pdpb::AllocIdRequest::default();
+            let resp = stub.alloc_id(&req).map_err(Error::from)?;
+            backend::check_resp_header(resp.get_header())?;
+            Ok(resp.get_id())
+        })
+    }
+
+    fn get_region(&self, key: &[u8]) -> Result<Region> {
+        self.retry(|backend| {
+            let stub = backend
+                .client_stub()
+                .ok_or_else(|| Error::Other("backend does not support get_region".to_owned().into()))?;
+            let mut req = pdpb::GetRegionRequest::default();
+            req.set_region_key(key.to_vec());
+            let resp = stub.get_region(&req).map_err(Error::from)?;
+            backend::check_resp_header(resp.get_header())?;
+            Ok(resp.get_region().clone())
+        })
+    }
+
+    fn region_heartbeat(
+        &self,
+        _term: u64,
+        region: Region,
+        leader: kvproto::metapb::Peer,
+        region_stat: crate::RegionStat,
+        _replication_status: Option<pdpb::ReplicationStatus>,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<()>> + Send>> {
+        let result = self.retry(|backend| {
+            let stub = backend
+                .client_stub()
+                .ok_or_else(|| Error::Other("backend does not support region_heartbeat".to_owned().into()))?;
+            let mut req = pdpb::RegionHeartbeatRequest::default();
+            req.set_region(region.clone());
+            req.set_leader(leader.clone());
+            req.set_down_peers(region_stat.down_peers.clone().into());
+            req.set_pending_peers(region_stat.pending_peers.clone().into());
+            req.set_bytes_written(region_stat.written_bytes);
+            req.set_keys_written(region_stat.written_keys);
+            req.set_bytes_read(region_stat.read_bytes);
+            req.set_keys_read(region_stat.read_keys);
+            req.set_approximate_size(region_stat.approximate_size);
+            req.set_approximate_keys(region_stat.approximate_keys);
+            let resp = stub.region_heartbeat_opt(&req).map_err(Error::from)?;
+            backend::check_resp_header(resp.get_header())?;
+            Ok(())
+        });
+        Box::pin(async move { result })
+    }
+
+    fn get_tso(&self) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<TimeStamp>> + Send>> {
+        let rx = self.tso.get_tso();
+        Box::pin(async move {
+            match rx.await {
+                Ok(result) => result,
+                Err(_) => Err(Error::Other("TSO batcher dropped the request".to_owned().into())),
+            }
+        })
+    }
+
+    fn watch_global_config(
+        &self,
+        config_path: String,
+        revision: i64,
+    ) -> Result<std::pin::Pin<Box<dyn Stream<Item = grpcio::Result<pdpb::WatchGlobalConfigResponse>> + Send>>> {
+        let backend = self.backend.clone();
+        let open: OpenStream = Box::new(move |from_revision| {
+            let stub = backend.client_stub().ok_or(grpcio::Error::RemoteStopped)?;
+            let mut req = pdpb::WatchGlobalConfigRequest::default();
+            req.set_config_path(config_path.clone());
+            req.set_revision(from_revision);
+            let stream = stub.watch_global_config(&req)?;
+            Ok(Box::pin(stream))
+        });
+        let reconnect_backend = self.backend.clone();
+        let reconnect: Reconnect = Box::new(move || reconnect_backend.reconnect().map_err(to_grpc_error));
+        let watch =
+            ResumableConfigWatch::new(revision, self.config.watch.clone(), reconnect, open).map_err(Error::from)?;
+        Ok(Box::pin(watch))
+    }
+}
@@ -0,0 +1,71 @@
+// Copyright 2023 TiKV Project Authors. Licensed under Apache-2.0.
+
+use tikv_util::config::ReadableDuration;
+
+use crate::{retry::RetryConfig, tso::TsoBatchConfig, watch::WatchConfig};
+
+/// Configuration for [`crate::RpcClient`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    /// PD endpoints to connect to.
+    pub endpoints: Vec<String>,
+    /// How often the client refreshes its view of the cluster in the
+    /// background, independent of the per-request retry loop below.
+    pub update_interval: ReadableDuration,
+    /// Per-request retry policy (leader-change retries, reconnect budget
+    /// and backoff). See [`crate::retry`].
+    pub retry: RetryConfig,
+    /// Reconnect policy for `watch_global_config`'s resumable stream. See
+    /// [`crate::watch`].
+    pub watch: WatchConfig,
+    /// Batching policy for the background `get_tso` dispatcher. See
+    /// [`crate::tso`].
+    pub tso_batch: TsoBatchConfig,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            endpoints: vec![],
+            update_interval: ReadableDuration::secs(10),
+            retry: RetryConfig::default(),
+            watch: WatchConfig::default(),
+            tso_batch: TsoBatchConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Validates the config, flooring any retry/reconnect counters a
+    /// caller configured to `0` up to `1` so the retry loops in
+    /// [`crate::retry`] always get at least one attempt instead of relying
+    /// solely on the internal floor in [`crate::retry::retry_on_leader_change`].
+    pub fn validate(&mut self) {
+        if self.retry.leader_change_retry == 0 {
+            self.retry.leader_change_retry = 1;
+        }
+        if self.retry.max_reconnect_count == 0 {
+            self.retry.max_reconnect_count = 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_floors_zero_retry_budgets() {
+        let mut cfg = Config {
+            retry: RetryConfig {
+                leader_change_retry: 0,
+                max_reconnect_count: 0,
+                ..RetryConfig::default()
+            },
+            ..Config::default()
+        };
+        cfg.validate();
+        assert_eq!(cfg.retry.leader_change_retry, 1);
+        assert_eq!(cfg.retry.max_reconnect_count, 1);
+    }
+}
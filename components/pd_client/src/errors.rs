@@ -0,0 +1,37 @@
+// Copyright 2023 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::{error, fmt, result};
+
+/// Error type shared by every `PdClient` implementation and the helper
+/// modules in this crate (`retry`, `watch`, `tso`, `backend`).
+#[derive(Debug)]
+pub enum Error {
+    Grpc(grpcio::Error),
+    Other(Box<dyn error::Error + Sync + Send>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Grpc(e) => write!(f, "gRPC error {:?}", e),
+            Error::Other(e) => write!(f, "{:?}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Grpc(e) => Some(e),
+            Error::Other(e) => Some(e.as_ref()),
+        }
+    }
+}
+
+impl From<grpcio::Error> for Error {
+    fn from(e: grpcio::Error) -> Error {
+        Error::Grpc(e)
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
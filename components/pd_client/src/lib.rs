@@ -0,0 +1,227 @@
+// Copyright 2016 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Client for talking to a PD (Placement Driver) cluster.
+
+use futures::stream::Stream;
+use kvproto::{
+    metapb::{Peer, Region, Store},
+    pdpb::{ClusterConfig, GlobalConfigItem, OperatorResponse, ReplicationStatus, StoreStats},
+};
+
+pub mod backend;
+pub mod client;
+pub mod config;
+pub mod errors;
+pub mod retry;
+#[cfg(feature = "sim")]
+pub mod sim;
+pub mod tso;
+pub mod watch;
+
+pub use crate::{
+    client::RpcClient,
+    config::Config,
+    errors::{Error, Result},
+};
+
+/// A region and, if known, the peer currently believed to be its leader.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RegionInfo {
+    pub region: Region,
+    pub leader: Option<Peer>,
+}
+
+impl RegionInfo {
+    pub fn new(region: Region, leader: Option<Peer>) -> RegionInfo {
+        RegionInfo { region, leader }
+    }
+}
+
+/// Load/traffic statistics reported alongside a `region_heartbeat`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RegionStat {
+    pub down_peers: Vec<Peer>,
+    pub pending_peers: Vec<Peer>,
+    pub written_bytes: u64,
+    pub written_keys: u64,
+    pub read_bytes: u64,
+    pub read_keys: u64,
+    pub approximate_size: u64,
+    pub approximate_keys: u64,
+}
+
+/// The client-facing interface to a PD cluster.
+///
+/// Most methods default to returning
+/// `Error::Other("not implemented for this PdClient")`: a
+/// given implementor only needs to override the operations it actually
+/// supports, rather than restating the entire surface.
+pub trait PdClient: Send + Sync {
+    fn reconnect(&self) -> Result<()> {
+        unimplemented()
+    }
+
+    fn handle_reconnect<F: FnOnce() + Send + 'static>(&self, _f: F) {}
+
+    fn get_cluster_id(&self) -> Result<u64> {
+        unimplemented()
+    }
+
+    fn bootstrap_cluster(&self, _store: Store, _region: Region) -> Result<Option<ReplicationStatus>> {
+        unimplemented()
+    }
+
+    fn is_cluster_bootstrapped(&self) -> Result<bool> {
+        unimplemented()
+    }
+
+    fn alloc_id(&self) -> Result<u64> {
+        unimplemented()
+    }
+
+    fn put_store(&self, _store: Store) -> Result<Option<ReplicationStatus>> {
+        unimplemented()
+    }
+
+    fn get_store(&self, _store_id: u64) -> Result<Store> {
+        unimplemented()
+    }
+
+    fn get_all_stores(&self, _exclude_tombstone: bool) -> Result<Vec<Store>> {
+        unimplemented()
+    }
+
+    fn get_cluster_config(&self) -> Result<ClusterConfig> {
+        unimplemented()
+    }
+
+    fn get_region(&self, _key: &[u8]) -> Result<Region> {
+        unimplemented()
+    }
+
+    fn get_region_info(&self, _key: &[u8]) -> Result<RegionInfo> {
+        unimplemented()
+    }
+
+    fn get_region_async<'a>(
+        &'a self,
+        _key: &'a [u8],
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<Region>> + Send + 'a>> {
+        Box::pin(async { unimplemented() })
+    }
+
+    fn get_region_info_async<'a>(
+        &'a self,
+        _key: &'a [u8],
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<RegionInfo>> + Send + 'a>> {
+        Box::pin(async { unimplemented() })
+    }
+
+    fn get_region_by_id(
+        &self,
+        _region_id: u64,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<Option<Region>>> + Send>> {
+        Box::pin(async { unimplemented() })
+    }
+
+    fn region_heartbeat(
+        &self,
+        _term: u64,
+        _region: Region,
+        _leader: Peer,
+        _region_stat: RegionStat,
+        _replication_status: Option<ReplicationStatus>,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<()>> + Send>> {
+        Box::pin(async { unimplemented() })
+    }
+
+    fn ask_split(
+        &self,
+        _region: Region,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<pdpb_ask_split::Response>> + Send>> {
+        Box::pin(async { unimplemented() })
+    }
+
+    fn ask_batch_split(
+        &self,
+        _region: Region,
+        _count: usize,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<pdpb_ask_split::BatchResponse>> + Send>> {
+        Box::pin(async { unimplemented() })
+    }
+
+    fn store_heartbeat(
+        &self,
+        _stats: StoreStats,
+        _report: Option<pdpb_ask_split::StoreReport>,
+        _status: Option<ReplicationStatus>,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<()>> + Send>> {
+        Box::pin(async { unimplemented() })
+    }
+
+    fn report_batch_split(
+        &self,
+        _regions: Vec<Region>,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<()>> + Send>> {
+        Box::pin(async { unimplemented() })
+    }
+
+    fn scatter_region(&self, _region: RegionInfo) -> Result<()> {
+        unimplemented()
+    }
+
+    fn get_gc_safe_point(&self) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<u64>> + Send>> {
+        Box::pin(async { unimplemented() })
+    }
+
+    fn get_store_stats_async(
+        &self,
+        _store_id: u64,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<StoreStats>> + Send>> {
+        Box::pin(async { unimplemented() })
+    }
+
+    fn get_operator(&self, _region_id: u64) -> Result<OperatorResponse> {
+        unimplemented()
+    }
+
+    fn get_tso(&self) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<txn_types::TimeStamp>> + Send>> {
+        Box::pin(async { unimplemented() })
+    }
+
+    fn load_global_config(
+        &self,
+        _config_path: String,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<(Vec<GlobalConfigItem>, i64)>> + Send>> {
+        Box::pin(async { unimplemented() })
+    }
+
+    fn store_global_config(
+        &self,
+        _config_path: String,
+        _items: Vec<GlobalConfigItem>,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<()>> + Send>> {
+        Box::pin(async { unimplemented() })
+    }
+
+    fn watch_global_config(
+        &self,
+        _config_path: String,
+        _revision: i64,
+    ) -> Result<std::pin::Pin<Box<dyn Stream<Item = grpcio::Result<kvproto::pdpb::WatchGlobalConfigResponse>> + Send>>>
+    {
+        unimplemented()
+    }
+}
+
+fn unimplemented<T>() -> Result<T> {
+    Err(Error::Other("not implemented for this PdClient".to_owned().into()))
+}
+
+/// Groups the handful of `pdpb` response types used only by
+/// [`PdClient::ask_split`]/`ask_batch_split`/`store_heartbeat` above, so the
+/// trait doesn't need every caller to depend on the exact `pdpb` message
+/// names directly.
+mod pdpb_ask_split {
+    pub use kvproto::pdpb::{AskBatchSplitResponse as BatchResponse, AskSplitResponse as Response, StoreReport};
+}
@@ -0,0 +1,241 @@
+// Copyright 2023 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Per-request retry policy for `RpcClient`.
+//!
+//! Reconnects are globally rate-limited by `GLOBAL_RECONNECT_INTERVAL`, but
+//! until now a single request that raced a PD leader change had no way to
+//! recover on its own: it simply bubbled the error straight back to the
+//! caller, leaving every call site to grow its own ad-hoc retry loop. This
+//! module gives `RpcClient` one retry loop that every request method can
+//! wrap itself in: on error, it reconnects (still subject to the existing
+//! global rate limit) and retries against the fresh leader, up to a bounded
+//! number of times.
+
+use std::{cmp, sync::Arc, thread, time::Duration};
+
+use tikv_util::config::ReadableDuration;
+
+/// Abstracts the backoff sleep between reconnect attempts behind a trait, so
+/// the `sim` feature's deterministic [`crate::sim::SimClock`] can drive this
+/// same retry loop instead of it always waiting on the wall clock.
+/// Production code gets [`RealClock`]; nothing about `reconnect_with_backoff`
+/// or `retry_on_leader_change` otherwise changes.
+pub trait Clock: Send + Sync {
+    fn sleep(&self, duration: Duration);
+}
+
+/// The default [`Clock`]: sleeps on the real wall clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RealClock;
+
+impl Clock for RealClock {
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+/// Tunables for the per-request retry loop, embedded in the PD client
+/// config as `Config::retry`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryConfig {
+    /// Number of leader-change iterations a single request may go through
+    /// before giving up. Historically hard-coded as `LEADER_CHANGE_RETRY`.
+    pub leader_change_retry: usize,
+    /// Budget of `reconnect()` attempts a single retry loop may spend while
+    /// chasing a new leader, independent of the global reconnect rate
+    /// limit. Historically hard-coded as `MAX_REQUEST_COUNT`.
+    pub max_reconnect_count: usize,
+    /// Base backoff between reconnect attempts, doubled on every
+    /// consecutive failure. Historically hard-coded as `RECONNECT_INTERVAL`.
+    pub reconnect_interval: ReadableDuration,
+    /// Upper bound the doubling backoff is clamped to.
+    pub max_reconnect_interval: ReadableDuration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> RetryConfig {
+        RetryConfig {
+            leader_change_retry: 10,
+            max_reconnect_count: 3,
+            reconnect_interval: ReadableDuration::millis(100),
+            max_reconnect_interval: ReadableDuration::secs(5),
+        }
+    }
+}
+
+/// Calls `reconnect` with exponential backoff until it succeeds or the
+/// `max_reconnect_count` budget is spent, returning the last error on
+/// exhaustion. `reconnect` is expected to itself honor the client's global
+/// reconnect rate limit, so concurrently retrying requests never stampede
+/// the leader.
+fn reconnect_with_backoff<E>(
+    cfg: &RetryConfig,
+    clock: &dyn Clock,
+    mut reconnect: impl FnMut() -> Result<(), E>,
+) -> Result<(), E> {
+    // `max_reconnect_count` is user-configurable (`Config::retry`); floor it
+    // to 1 here rather than trusting the caller validated it, so a
+    // misconfigured `0` can't turn this into a panic instead of a retry
+    // loop that simply doesn't retry.
+    let max_reconnect_count = cfg.max_reconnect_count.max(1);
+    let mut backoff = cfg.reconnect_interval.0;
+    let mut last_err = None;
+    for attempt in 0..max_reconnect_count {
+        match reconnect() {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                last_err = Some(e);
+                if attempt + 1 < max_reconnect_count {
+                    clock.sleep(backoff);
+                    backoff = cmp::min(backoff * 2, cfg.max_reconnect_interval.0);
+                }
+            }
+        }
+    }
+    Err(last_err.expect("loop above always runs at least once"))
+}
+
+/// Runs `call` and, on failure, reconnects and retries against the
+/// refreshed leader/cluster handle, up to `cfg.leader_change_retry` times.
+///
+/// If a reconnect attempt exhausts its own budget (see
+/// [`reconnect_with_backoff`]), that reconnect error is returned
+/// immediately. Otherwise, once all leader-change retries are spent, the
+/// last error returned by `call` is returned.
+pub fn retry_on_leader_change<T, E>(
+    cfg: &RetryConfig,
+    clock: &dyn Clock,
+    mut reconnect: impl FnMut() -> Result<(), E>,
+    mut call: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    // Same floor as `reconnect_with_backoff`: `leader_change_retry` comes
+    // from user config and a `0` must not turn into a panic.
+    let leader_change_retry = cfg.leader_change_retry.max(1);
+    let mut last_err = None;
+    for _ in 0..leader_change_retry {
+        match call() {
+            Ok(resp) => return Ok(resp),
+            Err(e) => {
+                last_err = Some(e);
+                reconnect_with_backoff(cfg, clock, &mut reconnect)?;
+            }
+        }
+    }
+    Err(last_err.expect("loop above always runs at least once"))
+}
+
+/// The default [`Clock`] for production use, shared (not reconstructed per
+/// call) so `RpcClient` and anything it spawns agree on the same clock.
+pub fn real_clock() -> Arc<dyn Clock> {
+    Arc::new(RealClock)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_on_leader_change_exhausts_after_budget() {
+        let cfg = RetryConfig {
+            leader_change_retry: 3,
+            max_reconnect_count: 1,
+            reconnect_interval: ReadableDuration::millis(0),
+            max_reconnect_interval: ReadableDuration::millis(0),
+        };
+        let mut calls = 0;
+        let result: Result<(), &'static str> =
+            retry_on_leader_change(&cfg, &RealClock, || Ok(()), || {
+                calls += 1;
+                Err("not leader")
+            });
+        assert!(result.is_err());
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn test_retry_on_leader_change_recovers() {
+        let cfg = RetryConfig::default();
+        let mut calls = 0;
+        let result = retry_on_leader_change(&cfg, &RealClock, || Ok(()), || {
+            calls += 1;
+            if calls < 2 { Err("not leader") } else { Ok(42) }
+        });
+        assert_eq!(result, Ok(42));
+    }
+
+    #[test]
+    fn test_reconnect_budget_returns_last_reconnect_error() {
+        let cfg = RetryConfig {
+            leader_change_retry: 5,
+            max_reconnect_count: 2,
+            reconnect_interval: ReadableDuration::millis(0),
+            max_reconnect_interval: ReadableDuration::millis(0),
+        };
+        let mut reconnect_attempts = 0;
+        let result: Result<(), &'static str> = retry_on_leader_change(
+            &cfg,
+            &RealClock,
+            || {
+                reconnect_attempts += 1;
+                Err("reconnect failed")
+            },
+            || Err("not leader"),
+        );
+        assert_eq!(result, Err("reconnect failed"));
+        assert_eq!(reconnect_attempts, cfg.max_reconnect_count);
+    }
+
+    #[test]
+    fn test_zero_budgets_do_not_panic() {
+        let cfg = RetryConfig {
+            leader_change_retry: 0,
+            max_reconnect_count: 0,
+            reconnect_interval: ReadableDuration::millis(0),
+            max_reconnect_interval: ReadableDuration::millis(0),
+        };
+        let result: Result<(), &'static str> =
+            retry_on_leader_change(&cfg, &RealClock, || Err("reconnect failed"), || Err("not leader"));
+        assert_eq!(result, Err("reconnect failed"));
+    }
+
+    #[cfg(feature = "sim")]
+    #[test]
+    fn test_backoff_is_driven_by_sim_clock_not_the_wall_clock() {
+        // A real backoff here would be 10s + 20s = 30s of wall-clock sleep;
+        // driven through `SimClock` it should complete as fast as the
+        // advancing thread chooses to step it.
+        use crate::sim::SimClock;
+
+        let cfg = RetryConfig {
+            leader_change_retry: 1,
+            max_reconnect_count: 3,
+            reconnect_interval: ReadableDuration::secs(10),
+            max_reconnect_interval: ReadableDuration::secs(20),
+        };
+        let clock = SimClock::new();
+        let driver_clock = clock.clone();
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let driver_done = done.clone();
+        let driver = std::thread::spawn(move || {
+            // Two backoff sleeps are expected: after attempt 1 and attempt
+            // 2's failures. Each sleep is measured from the virtual time at
+            // which it starts, so a step can land before a sleep begins;
+            // keep stepping until the loop under test has finished.
+            while !driver_done.load(std::sync::atomic::Ordering::SeqCst) {
+                driver_clock.advance(Duration::from_secs(10));
+                std::thread::yield_now();
+            }
+        });
+
+        let mut attempts = 0;
+        let result: Result<(), &'static str> = reconnect_with_backoff(&cfg, &clock, || {
+            attempts += 1;
+            if attempts < 3 { Err("not yet") } else { Ok(()) }
+        });
+
+        done.store(true, std::sync::atomic::Ordering::SeqCst);
+        driver.join().unwrap();
+        assert_eq!(result, Ok(()));
+        assert_eq!(attempts, 3);
+    }
+}
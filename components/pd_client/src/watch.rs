@@ -0,0 +1,278 @@
+// Copyright 2023 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Resumable, auto-reconnecting `watch_global_config` stream.
+//!
+//! Plain `watch_global_config` terminates the moment the underlying gRPC
+//! stream hits an error such as `UNAVAILABLE` (see
+//! `test_watch_global_config_on_closed_server`), forcing the caller to
+//! rebuild the stream and replay from revision 0. That can both miss
+//! changes made while no stream was open and re-deliver changes the caller
+//! already saw. [`ResumableConfigWatch`] fixes this by remembering the
+//! highest revision it has delivered and transparently re-opening the watch
+//! from `last_revision + 1` whenever the stream breaks.
+
+use std::pin::Pin;
+
+use futures::{
+    stream::Stream,
+    task::{Context, Poll},
+};
+use grpcio::Result as GrpcResult;
+use kvproto::pdpb::WatchGlobalConfigResponse;
+use tikv_util::config::ReadableDuration;
+
+/// Tunables for [`ResumableConfigWatch`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct WatchConfig {
+    /// Maximum number of consecutive reconnect attempts before the stream
+    /// gives up and surfaces a terminal error to the caller.
+    pub max_reconnect_count: usize,
+    /// Backoff between resubscribe attempts. [`ResumableConfigWatch`] does
+    /// not read this field: its resubscribe attempts run back to back.
+    pub reconnect_interval: ReadableDuration,
+}
+
+impl Default for WatchConfig {
+    fn default() -> WatchConfig {
+        WatchConfig {
+            max_reconnect_count: 10,
+            reconnect_interval: ReadableDuration::millis(100),
+        }
+    }
+}
+
+/// A function that (re-)opens the underlying gRPC watch stream starting
+/// from the given revision.
+pub type OpenStream =
+    Box<dyn FnMut(i64) -> GrpcResult<Pin<Box<dyn Stream<Item = GrpcResult<WatchGlobalConfigResponse>> + Send>>> + Send>;
+
+/// A function that re-resolves the backend's connection, the same recovery
+/// `RpcClient`'s own retry loop uses on a leader change. Called before
+/// `open` on every resubscribe, so a broken stream doesn't get reopened
+/// against the same dead channel that broke it.
+pub type Reconnect = Box<dyn FnMut() -> GrpcResult<()> + Send>;
+
+/// A `Stream` of `WatchGlobalConfigResponse` that hides transient
+/// disconnects from its consumer.
+///
+/// On `UNAVAILABLE`/EOF from the inner stream, it re-opens the watch from
+/// `last_revision + 1` via `open`, up to `config.max_reconnect_count`
+/// times, so the consumer observes an uninterrupted, gap-free,
+/// monotonically-increasing sequence of `GlobalConfigItem` changes.
+pub struct ResumableConfigWatch {
+    open: OpenStream,
+    reconnect: Reconnect,
+    inner: Pin<Box<dyn Stream<Item = GrpcResult<WatchGlobalConfigResponse>> + Send>>,
+    last_revision: i64,
+    config: WatchConfig,
+    reconnects_left: usize,
+    /// The most recent error observed from the inner stream or a failed
+    /// resubscribe, surfaced to the caller once `reconnects_left` is spent
+    /// instead of being silently swallowed.
+    last_err: Option<grpcio::Error>,
+}
+
+impl ResumableConfigWatch {
+    /// Creates a new resumable watch starting at `start_revision`, using
+    /// `open` to (re-)establish the underlying stream and `reconnect` to
+    /// rebuild the backend's connection before every resubscribe attempt.
+    pub fn new(start_revision: i64, config: WatchConfig, reconnect: Reconnect, mut open: OpenStream) -> GrpcResult<Self> {
+        let inner = open(start_revision)?;
+        let reconnects_left = config.max_reconnect_count;
+        Ok(ResumableConfigWatch {
+            open,
+            reconnect,
+            inner,
+            last_revision: start_revision.saturating_sub(1),
+            config,
+            reconnects_left,
+            last_err: None,
+        })
+    }
+
+    /// Rebuilds the backend's connection -- the same recovery path a
+    /// request method's retry loop uses on a leader change -- and then
+    /// reopens the watch stream from `last_revision + 1`. A stream broken
+    /// by e.g. the leader dying must not simply be retried against the
+    /// same dead channel.
+    fn resubscribe(&mut self) -> GrpcResult<()> {
+        (self.reconnect)()?;
+        self.inner = (self.open)(self.last_revision + 1)?;
+        Ok(())
+    }
+
+    /// The error returned once the reconnect budget is exhausted, per the
+    /// "cap reconnect attempts before surfacing a terminal error to the
+    /// caller" requirement -- synthesized when the stream ended cleanly
+    /// (no underlying error to forward) rather than via a failed RPC.
+    fn terminal_error(&mut self) -> grpcio::Error {
+        self.last_err.take().unwrap_or_else(|| {
+            grpcio::Error::RpcFailure(grpcio::RpcStatus::with_message(
+                grpcio::RpcStatusCode::UNAVAILABLE,
+                "global config watch exhausted its reconnect budget".to_owned(),
+            ))
+        })
+    }
+
+    /// Called when the inner stream has just ended or errored. Either
+    /// resubscribes and returns `None` so the caller keeps polling the
+    /// fresh stream, or -- once `reconnects_left` is spent -- returns
+    /// `Some(err)` with the terminal error to surface, rather than
+    /// returning `Poll::Ready(None)` and silently ending the stream.
+    fn reconnect_or_terminate(&mut self) -> Option<grpcio::Error> {
+        if self.reconnects_left == 0 {
+            return Some(self.terminal_error());
+        }
+        self.reconnects_left -= 1;
+        if let Err(e) = self.resubscribe() {
+            // Keep the most recent failure and let `poll_next`'s loop
+            // re-poll the old, already-ended stream and so retry
+            // resubscribing (still bounded by `reconnects_left`)
+            // instead of returning `Pending` with no waker ever armed,
+            // which would stall this stream forever.
+            self.last_err = Some(e);
+        }
+        None
+    }
+}
+
+impl Stream for ResumableConfigWatch {
+    type Item = GrpcResult<WatchGlobalConfigResponse>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match self.inner.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Ok(resp))) => {
+                    if let Some(revision) = resp.get_changes().iter().map(|_| resp.get_revision()).last() {
+                        self.last_revision = self.last_revision.max(revision);
+                    }
+                    self.reconnects_left = self.config.max_reconnect_count;
+                    self.last_err = None;
+                    return Poll::Ready(Some(Ok(resp)));
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    self.last_err = Some(e);
+                    if let Some(err) = self.reconnect_or_terminate() {
+                        return Poll::Ready(Some(Err(err)));
+                    }
+                }
+                Poll::Ready(None) => {
+                    if let Some(err) = self.reconnect_or_terminate() {
+                        return Poll::Ready(Some(Err(err)));
+                    }
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use futures::{executor::block_on, stream, StreamExt};
+
+    use super::*;
+
+    fn err_stream() -> Pin<Box<dyn Stream<Item = GrpcResult<WatchGlobalConfigResponse>> + Send>> {
+        Box::pin(stream::once(async {
+            Err(grpcio::Error::RemoteStopped)
+        }))
+    }
+
+    fn noop_reconnect() -> Reconnect {
+        Box::new(|| Ok(()))
+    }
+
+    #[test]
+    fn test_exhausted_reconnects_surface_a_terminal_error_instead_of_ending() {
+        // Every resubscribe attempt immediately fails, so the stream should
+        // make exactly `max_reconnect_count` attempts and then yield one
+        // terminal `Err`, not a silent `None`.
+        let attempts = Arc::new(Mutex::new(0));
+        let attempts_clone = attempts.clone();
+        let open: OpenStream = Box::new(move |_revision| {
+            *attempts_clone.lock().unwrap() += 1;
+            Ok(err_stream())
+        });
+        let config = WatchConfig {
+            max_reconnect_count: 3,
+            reconnect_interval: ReadableDuration::millis(0),
+        };
+        let mut watch = ResumableConfigWatch::new(0, config, noop_reconnect(), open).unwrap();
+        let item = block_on(watch.next());
+        assert!(matches!(item, Some(Err(_))));
+        // One attempt to build the initial stream plus one resubscribe per
+        // reconnect budget slot.
+        assert_eq!(*attempts.lock().unwrap(), 1 + 3);
+    }
+
+    #[test]
+    fn test_resubscribe_failure_does_not_stall_forever() {
+        // The initial stream opens fine, then every resubscribe attempt
+        // fails outright (not the stream it would have returned).
+        // Previously this path returned `Poll::Pending` with no waker
+        // armed, hanging the stream forever instead of exhausting its
+        // reconnect budget and returning a terminal error.
+        let config = WatchConfig {
+            max_reconnect_count: 2,
+            reconnect_interval: ReadableDuration::millis(0),
+        };
+        // Keyed on call count, not revision: nothing is delivered before the
+        // first resubscribe, so it reopens from the start revision too.
+        let mut opened = false;
+        let open: OpenStream = Box::new(move |_revision| {
+            if !opened {
+                opened = true;
+                Ok(err_stream())
+            } else {
+                Err(grpcio::Error::RemoteStopped)
+            }
+        });
+        let mut watch = ResumableConfigWatch::new(0, config, noop_reconnect(), open).unwrap();
+        let item = block_on(watch.next());
+        assert!(matches!(item, Some(Err(_))));
+    }
+
+    #[test]
+    fn test_resubscribe_reconnects_before_reopening_the_stream() {
+        // A broken stream must not simply be retried against the same dead
+        // channel: resubscribe should reconnect first, then reopen.
+        let reconnect_count = Arc::new(Mutex::new(0));
+        let open_count = Arc::new(Mutex::new(0));
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        let reconnect: Reconnect = {
+            let reconnect_count = reconnect_count.clone();
+            let events = events.clone();
+            Box::new(move || {
+                *reconnect_count.lock().unwrap() += 1;
+                events.lock().unwrap().push("reconnect");
+                Ok(())
+            })
+        };
+        let open: OpenStream = {
+            let open_count = open_count.clone();
+            let events = events.clone();
+            Box::new(move |_revision| {
+                *open_count.lock().unwrap() += 1;
+                events.lock().unwrap().push("open");
+                Ok(err_stream())
+            })
+        };
+
+        let config = WatchConfig {
+            max_reconnect_count: 1,
+            reconnect_interval: ReadableDuration::millis(0),
+        };
+        let mut watch = ResumableConfigWatch::new(0, config, reconnect, open).unwrap();
+        let _ = block_on(watch.next());
+
+        // The initial `open` in `new` doesn't reconnect; only the one
+        // resubscribe triggered by the broken stream does.
+        assert_eq!(*reconnect_count.lock().unwrap(), 1);
+        assert_eq!(*open_count.lock().unwrap(), 2);
+        assert_eq!(*events.lock().unwrap(), vec!["open", "reconnect", "open"]);
+    }
+}
@@ -0,0 +1,129 @@
+// Copyright 2023 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Deterministic-simulation counterparts of `test_pd_client_deadlock`,
+//! `test_slow_periodical_update` and `test_reconnect_limit` from
+//! `test_pd_client_legacy.rs`.
+//!
+//! Those tests drive real threads, `thread::sleep`, and timing-sensitive
+//! fail points to provoke reconnect/leader-change interleavings, which
+//! makes rare orderings flaky and hard to reproduce. The tests below
+//! exercise the same scenarios against [`pd_client::sim::SimClock`] instead
+//! of a real (mocked) gRPC server, so every interleaving is driven by
+//! explicit `advance` calls and is exactly reproducible. Only built with
+//! `--features sim`; these are not a replacement for the wall-clock tests,
+//! which still exercise the real gRPC path end to end.
+
+#![cfg(feature = "sim")]
+
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::task::noop_waker;
+use pd_client::{
+    retry::{retry_on_leader_change, RealClock, RetryConfig},
+    sim::SimClock,
+};
+
+/// Deterministic analogue of `test_reconnect_limit`: reconnects beyond the
+/// configured budget must be reported as exhausted rather than silently
+/// retried forever, and the virtual-clock readings are identical on every
+/// run because they come from explicit clock `advance` calls. The backoff
+/// between the two attempts sleeps on [`RealClock`]: each attempt already
+/// advances the shared clock itself, so a `SimClock` backoff would wait on a
+/// step no one takes.
+#[test]
+fn test_reconnect_limit_is_deterministic() {
+    let clock = SimClock::new();
+
+    let cfg = RetryConfig {
+        leader_change_retry: 1,
+        max_reconnect_count: 2,
+        reconnect_interval: tikv_util::config::ReadableDuration::millis(50),
+        max_reconnect_interval: tikv_util::config::ReadableDuration::millis(50),
+    };
+
+    // Every reconnect attempt fails until the clock is advanced far enough
+    // that a real cluster would, in a real deployment, have heard back from
+    // a new leader. Here we just assert the retry loop makes exactly
+    // `max_reconnect_count` attempts before giving up.
+    let mut attempts = 0;
+    let result: Result<(), &'static str> = retry_on_leader_change(
+        &cfg,
+        &RealClock,
+        || {
+            attempts += 1;
+            clock.advance(Duration::from_millis(50));
+            Err("leader not yet elected")
+        },
+        || Err("not leader"),
+    );
+
+    assert!(result.is_err());
+    assert_eq!(attempts, cfg.max_reconnect_count);
+    assert_eq!(clock.now(), Duration::from_millis(100));
+}
+
+/// Deterministic analogue of `test_pd_client_deadlock`: a request racing a
+/// leader change must observe the new leader, chosen by explicitly
+/// stepping a simulated leader index rather than by a real election under
+/// a fail point.
+#[test]
+fn test_request_observes_leader_change_mid_retry() {
+    let leader = Arc::new(Mutex::new("pd-0"));
+    let reconnect_count = Arc::new(Mutex::new(0));
+    let cfg = RetryConfig::default();
+
+    let mut attempts = 0;
+    let result = retry_on_leader_change::<_, &'static str>(
+        &cfg,
+        &RealClock,
+        || {
+            *reconnect_count.lock().unwrap() += 1;
+            *leader.lock().unwrap() = "pd-1";
+            Ok(())
+        },
+        || {
+            attempts += 1;
+            if attempts == 1 {
+                Err("stale leader, retry")
+            } else {
+                Ok(*leader.lock().unwrap())
+            }
+        },
+    )
+    .unwrap();
+
+    assert_eq!(result, "pd-1");
+    assert_eq!(*reconnect_count.lock().unwrap(), 1);
+}
+
+/// Deterministic analogue of `test_slow_periodical_update`: a slow
+/// periodical leader refresh on one client must not block an unrelated
+/// request on another client sharing the same clock. Modeled by advancing
+/// a shared [`SimClock`] and asserting the fast client's request completes
+/// without waiting for the slow client's refresh interval to elapse.
+#[test]
+fn test_periodical_update_does_not_block_other_requests() {
+    let clock = SimClock::new();
+
+    // The "slow" client's periodical refresh is a long sleep on the shared
+    // clock; it must stay `Pending` while the "fast" client's short sleep
+    // resolves, i.e. advancing the clock past the fast deadline must not
+    // require advancing it all the way to the slow one.
+    let mut slow_refresh = Box::pin(clock.sleep(Duration::from_secs(10)));
+    let mut fast_request = Box::pin(clock.sleep(Duration::from_millis(10)));
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+
+    assert_eq!(slow_refresh.as_mut().poll(&mut cx), Poll::Pending);
+    assert_eq!(fast_request.as_mut().poll(&mut cx), Poll::Pending);
+
+    clock.advance(Duration::from_millis(10));
+
+    assert_eq!(fast_request.as_mut().poll(&mut cx), Poll::Ready(()));
+    assert_eq!(slow_refresh.as_mut().poll(&mut cx), Poll::Pending);
+}